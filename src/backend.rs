@@ -0,0 +1,258 @@
+use std::str;
+use std::path::Path;
+use std::process::{Command, Stdio, Output};
+
+use conf::{Backend, Project};
+use error::{Reason, Result, Error};
+use git2_backend::NativeGitBackend;
+
+pub type BytesResult = Result<Vec<u8>>;
+
+pub trait VcsBackend {
+    fn clone_repo(&self, bin: &str, path: &Path, url: &str) -> BytesResult;
+    fn checkout(&self, bin: &str, path: &Path, branch: &str) -> BytesResult;
+    fn remote_update(&self, bin: &str, path: &Path) -> BytesResult;
+    fn pull(&self, bin: &str, path: &Path, branch: &str) -> BytesResult;
+    fn remote_changed(&self, bin: &str, path: &Path, branch: &str) -> Result<bool>;
+    fn head_sha(&self, bin: &str, path: &Path) -> Result<String>;
+
+    fn clone_mirror(&self, _bin: &str, _path: &Path, _url: &str) -> BytesResult {
+        Err(unsupported("mirror mode"))
+    }
+
+    fn update_mirror(&self, _bin: &str, _path: &Path) -> Result<bool> {
+        Err(unsupported("mirror mode"))
+    }
+
+    fn lfs_fetch(&self, _bin: &str, _path: &Path) -> BytesResult {
+        Err(unsupported("git-lfs"))
+    }
+
+    /// Paths touched between two revisions, for backends that can report it. The default
+    /// implementation returns an empty list, meaning "not available".
+    fn changed_files(&self, _bin: &str, _path: &Path, _old: &str, _new: &str) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// One-line-per-commit log between two revisions, `\0`-separated sha/subject.
+    fn commit_log(&self, _bin: &str, _path: &Path, _old: &str, _new: &str) -> Result<String> {
+        Ok(String::new())
+    }
+}
+
+fn unsupported(feature: &str) -> Error {
+    Error::app(Reason::CommandFailed, format!("{} is not supported by this backend", feature))
+}
+
+pub fn for_project(project: &Project) -> Box<VcsBackend> {
+    match project.backend {
+        Backend::Git if project.native => Box::new(NativeGitBackend::new(project.credentials.clone())),
+        Backend::Git => Box::new(GitBackend),
+        Backend::Mercurial => Box::new(MercurialBackend),
+    }
+}
+
+pub struct GitBackend;
+
+impl VcsBackend for GitBackend {
+    fn clone_repo(&self, bin: &str, path: &Path, url: &str) -> BytesResult {
+        let path_s = try!(path.to_str().ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
+        info!("Cloning {} to {}", url, path_s);
+        run(Command::new(bin)
+                .arg("clone")
+                .arg(url)
+                .arg(path_s),
+            "git clone")
+    }
+
+    fn checkout(&self, bin: &str, path: &Path, branch: &str) -> BytesResult {
+        info!("Checking out branch {} in {}", branch, path_str(path));
+        run(Command::new(bin)
+                .arg("checkout")
+                .arg(branch)
+                .current_dir(path),
+            "git checkout")
+    }
+
+    fn remote_update(&self, bin: &str, path: &Path) -> BytesResult {
+        info!("Updating remotes in {}", path_str(path));
+        run(Command::new(bin)
+                .arg("remote")
+                .arg("update")
+                .current_dir(path),
+            "git remote update")
+    }
+
+    fn pull(&self, bin: &str, path: &Path, _branch: &str) -> BytesResult {
+        info!("Pulling changes in {}", path_str(path));
+        run(Command::new(bin).arg("pull").current_dir(path), "git pull")
+    }
+
+    fn remote_changed(&self, bin: &str, path: &Path, _branch: &str) -> Result<bool> {
+        let local = try!(run(Command::new(bin)
+                                 .arg("rev-parse")
+                                 .arg("@")
+                                 .current_dir(path),
+                             "git rev-parse"));
+        let remote = try!(run(Command::new(bin)
+                                  .arg("rev-parse")
+                                  .arg("@{u}")
+                                  .current_dir(path),
+                              "git rev-parse"));
+        Ok(local != remote)
+    }
+
+    fn head_sha(&self, bin: &str, path: &Path) -> Result<String> {
+        let out = try!(run(Command::new(bin).arg("rev-parse").arg("HEAD").current_dir(path),
+                           "git rev-parse HEAD"));
+        trimmed(out)
+    }
+
+    fn clone_mirror(&self, bin: &str, path: &Path, url: &str) -> BytesResult {
+        let path_s = try!(path.to_str().ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
+        info!("Cloning mirror of {} to {}", url, path_s);
+        run(Command::new(bin)
+                .arg("clone")
+                .arg("--mirror")
+                .arg(url)
+                .arg(path_s),
+            "git clone --mirror")
+    }
+
+    fn update_mirror(&self, bin: &str, path: &Path) -> Result<bool> {
+        info!("Updating mirror in {}", path_str(path));
+        let before = try!(run(Command::new(bin).arg("rev-parse").arg("--all").current_dir(path),
+                              "git rev-parse --all"));
+        let _ = try!(run(Command::new(bin)
+                             .arg("remote")
+                             .arg("update")
+                             .arg("--prune")
+                             .arg("origin")
+                             .current_dir(path),
+                         "git remote update --prune"));
+        let after = try!(run(Command::new(bin).arg("rev-parse").arg("--all").current_dir(path),
+                             "git rev-parse --all"));
+        Ok(before != after)
+    }
+
+    fn lfs_fetch(&self, bin: &str, path: &Path) -> BytesResult {
+        info!("Fetching LFS objects in {}", path_str(path));
+        run(Command::new(bin)
+                .arg("lfs")
+                .arg("fetch")
+                .arg("--all")
+                .current_dir(path),
+            "git lfs fetch --all")
+    }
+
+    fn changed_files(&self, bin: &str, path: &Path, old: &str, new: &str) -> Result<Vec<String>> {
+        let range = format!("{}..{}", old, new);
+        let out = try!(run(Command::new(bin)
+                               .arg("diff")
+                               .arg("--name-only")
+                               .arg(&range)
+                               .current_dir(path),
+                           "git diff --name-only"));
+        let text = try!(str::from_utf8(&out).map_err(|_| Error::app(Reason::CommandFailed, "non-utf8 output")));
+        Ok(text.lines().map(str::to_owned).collect())
+    }
+
+    fn commit_log(&self, bin: &str, path: &Path, old: &str, new: &str) -> Result<String> {
+        let range = format!("{}..{}", old, new);
+        let out = try!(run(Command::new(bin)
+                               .arg("log")
+                               .arg("--format=%H%x00%s")
+                               .arg(&range)
+                               .current_dir(path),
+                           "git log"));
+        let text = try!(str::from_utf8(&out).map_err(|_| Error::app(Reason::CommandFailed, "non-utf8 output")));
+        Ok(text.trim_end().to_owned())
+    }
+}
+
+pub struct MercurialBackend;
+
+impl VcsBackend for MercurialBackend {
+    fn clone_repo(&self, bin: &str, path: &Path, url: &str) -> BytesResult {
+        let path_s = try!(path.to_str().ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
+        info!("Cloning {} to {}", url, path_s);
+        run(Command::new(bin)
+                .arg("clone")
+                .arg(url)
+                .arg(path_s),
+            "hg clone")
+    }
+
+    fn checkout(&self, bin: &str, path: &Path, branch: &str) -> BytesResult {
+        info!("Updating to branch {} in {}", branch, path_str(path));
+        run(Command::new(bin)
+                .arg("update")
+                .arg(branch)
+                .current_dir(path),
+            "hg update")
+    }
+
+    fn remote_update(&self, bin: &str, path: &Path) -> BytesResult {
+        info!("Pulling remote changesets in {}", path_str(path));
+        run(Command::new(bin).arg("pull").current_dir(path), "hg pull")
+    }
+
+    fn pull(&self, bin: &str, path: &Path, _branch: &str) -> BytesResult {
+        info!("Updating working copy in {}", path_str(path));
+        run(Command::new(bin).arg("update").current_dir(path), "hg update")
+    }
+
+    fn remote_changed(&self, bin: &str, path: &Path, _branch: &str) -> Result<bool> {
+        let local = try!(run(Command::new(bin)
+                                 .arg("id")
+                                 .arg("-i")
+                                 .current_dir(path),
+                             "hg id"));
+        let tip = try!(run(Command::new(bin)
+                               .arg("id")
+                               .arg("-i")
+                               .arg("-r")
+                               .arg("tip")
+                               .current_dir(path),
+                           "hg id"));
+        Ok(local != tip)
+    }
+
+    fn head_sha(&self, bin: &str, path: &Path) -> Result<String> {
+        let out = try!(run(Command::new(bin).arg("id").arg("-i").current_dir(path), "hg id"));
+        trimmed(out)
+    }
+}
+
+fn trimmed(bytes: Vec<u8>) -> Result<String> {
+    let text = try!(str::from_utf8(&bytes).map_err(|_| Error::app(Reason::CommandFailed, "non-utf8 output")));
+    Ok(text.trim().to_owned())
+}
+
+pub fn path_str(path: &Path) -> &str {
+    path.to_str().unwrap_or("[unprintable path]")
+}
+
+pub fn run(command: &mut Command, name: &str) -> BytesResult {
+    command.stdin(Stdio::null())
+           .output()
+           .map_err(Error::from)
+           .and_then(|out| non_zero_to_error(name, out))
+}
+
+fn non_zero_to_error(cmd: &str, out: Output) -> BytesResult {
+    if out.status.success() {
+        Ok(out.stdout)
+    } else {
+        Err(output_to_error(cmd, out))
+    }
+}
+
+fn output_to_error(cmd: &str, out: Output) -> Error {
+    let text = str::from_utf8(&out.stderr).unwrap_or("[invalid string]");
+    let msg = format!("Command {} exited with status {}: {}",
+                      cmd,
+                      out.status,
+                      text);
+    Error::app(Reason::CommandFailed, msg)
+}
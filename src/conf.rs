@@ -0,0 +1,280 @@
+use ini::{Ini, Properties};
+
+use error::{Error, Reason, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Git,
+    Mercurial,
+}
+
+impl Backend {
+    fn from_str(s: &str) -> Result<Backend> {
+        match s {
+            "git" => Ok(Backend::Git),
+            "hg" | "mercurial" => Ok(Backend::Mercurial),
+            other => {
+                Err(Error::app(Reason::InvalidConfig,
+                                format!("Unknown backend '{}'", other)))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Credentials {
+    SshKey {
+        key_path: String,
+        passphrase: Option<String>,
+    },
+    HttpsToken { token: String },
+    /// Runs `program` and reads its stdout as a secret. With `ssh_key_path` set, that secret
+    /// unlocks the passphrase-protected key; otherwise it's used as an HTTPS password.
+    Askpass {
+        program: String,
+        ssh_key_path: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug)]
+pub struct Project {
+    pub id: String,
+    pub repo: String,
+    pub url: String,
+    pub branch: String,
+    pub command: String,
+    pub backend: Backend,
+    pub native: bool,
+    pub credentials: Option<Credentials>,
+    pub status_token: Option<String>,
+    pub mirror: bool,
+    pub lfs: bool,
+    pub remote: RemoteUrl,
+}
+
+impl Project {
+    /// `owner/repo` as parsed from the configured remote, e.g. what a webhook payload's
+    /// `repository.full_name` would read on GitHub-shaped forges.
+    pub fn slug(&self) -> String {
+        format!("{}/{}", self.remote.owner, self.remote.repo)
+    }
+}
+
+/// The remote URL a project was configured with, split into its parts.
+///
+/// Used to key webhook payload matching off the configured forge instead of
+/// assuming everything lives on github.com.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteUrl {
+    pub scheme: String,
+    pub host: String,
+    pub owner: String,
+    pub repo: String,
+}
+
+impl RemoteUrl {
+    pub fn parse(url: &str) -> Result<RemoteUrl> {
+        if url.starts_with("git@") {
+            let rest = &url["git@".len()..];
+            let colon = try!(rest.find(':')
+                                  .ok_or_else(|| {
+                                      Error::app(Reason::InvalidConfig,
+                                                 format!("Remote url '{}' is missing ':'", url))
+                                  }));
+            let host = &rest[..colon];
+            let path = rest[colon + 1..].trim_end_matches(".git");
+            return split_owner_repo("ssh", host, path);
+        }
+        for scheme in &["https://", "http://", "ssh://"] {
+            if let Some(rest) = url_strip_prefix(url, scheme) {
+                let slash = try!(rest.find('/')
+                                      .ok_or_else(|| {
+                                          Error::app(Reason::InvalidConfig,
+                                                     format!("Remote url '{}' is missing a path", url))
+                                      }));
+                let host = &rest[..slash];
+                let path = rest[slash + 1..].trim_end_matches(".git");
+                return split_owner_repo(scheme.trim_end_matches("://"), host, path);
+            }
+        }
+        Err(Error::app(Reason::InvalidConfig, format!("Unrecognized remote url '{}'", url)))
+    }
+}
+
+fn url_strip_prefix<'a>(url: &'a str, prefix: &str) -> Option<&'a str> {
+    if url.starts_with(prefix) {
+        Some(&url[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+fn split_owner_repo(scheme: &str, host: &str, path: &str) -> Result<RemoteUrl> {
+    let slash = try!(path.find('/')
+                         .ok_or_else(|| {
+                             Error::app(Reason::InvalidConfig,
+                                        format!("Remote path '{}' is missing owner/repo", path))
+                         }));
+    Ok(RemoteUrl {
+        scheme: scheme.to_owned(),
+        host: host.to_owned(),
+        owner: path[..slash].to_owned(),
+        repo: path[slash + 1..].to_owned(),
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct Conf {
+    pub server: String,
+    pub threads: usize,
+    pub exec_threads: usize,
+    pub location: String,
+    pub gitpath: String,
+    pub hgpath: String,
+    pub queue_path: String,
+    pub max_attempts: u32,
+    pub backoff_base_secs: i64,
+    pub projects: Vec<Project>,
+}
+
+impl Conf {
+    pub fn from_file(path: &str) -> Result<Conf> {
+        let ini = try!(Ini::load_from_file(path)
+                           .map_err(|e| Error::app(Reason::InvalidConfig, format!("{}", e))));
+
+        let general = ini.general_section();
+        let server = general.get("server").cloned().unwrap_or_else(|| "0.0.0.0:8080".into());
+        let threads = try!(general.get("threads")
+                                  .map(|v| {
+                                      v.parse()
+                                       .map_err(|_| {
+                                           Error::app(Reason::InvalidConfig, "Invalid threads value")
+                                       })
+                                  })
+                                  .unwrap_or(Ok(4)));
+        let exec_threads = try!(general.get("exec_threads")
+                                       .map(|v| {
+                                           v.parse()
+                                            .map_err(|_| {
+                                                Error::app(Reason::InvalidConfig, "Invalid exec_threads value")
+                                            })
+                                       })
+                                       .unwrap_or(Ok(4)));
+        let location = general.get("location").cloned().unwrap_or_else(|| ".".into());
+        let gitpath = general.get("git").cloned().unwrap_or_else(|| "git".into());
+        let hgpath = general.get("hg").cloned().unwrap_or_else(|| "hg".into());
+        let queue_path = general.get("queue").cloned().unwrap_or_else(|| "koukku.db".into());
+        let max_attempts = try!(general.get("max_attempts")
+                                       .map(|v| {
+                                           v.parse()
+                                            .map_err(|_| {
+                                                Error::app(Reason::InvalidConfig, "Invalid max_attempts value")
+                                            })
+                                       })
+                                       .unwrap_or(Ok(5)));
+        let backoff_base_secs = try!(general.get("backoff_base_secs")
+                                             .map(|v| {
+                                                 v.parse()
+                                                  .map_err(|_| {
+                                                      Error::app(Reason::InvalidConfig,
+                                                                 "Invalid backoff_base_secs value")
+                                                  })
+                                             })
+                                             .unwrap_or(Ok(30)));
+
+        let mut projects = Vec::new();
+        for (section, props) in ini.iter() {
+            let id = match section {
+                Some(name) if name != "DEFAULT" => name,
+                _ => continue,
+            };
+            let repo = try!(props.get("repo")
+                                  .ok_or_else(|| {
+                                      Error::app(Reason::InvalidConfig,
+                                                 format!("Project {} is missing 'repo'", id))
+                                  }));
+            let branch = props.get("branch").cloned().unwrap_or_else(|| "master".into());
+            let command = try!(props.get("command")
+                                     .ok_or_else(|| {
+                                         Error::app(Reason::InvalidConfig,
+                                                    format!("Project {} is missing 'command'", id))
+                                     }));
+            let backend = try!(props.get("backend")
+                                     .map(|b| Backend::from_str(b))
+                                     .unwrap_or(Ok(Backend::Git)));
+            let native = props.get("native")
+                              .map(|v| v == "true" || v == "1")
+                              .unwrap_or(false);
+            let credentials = try!(parse_credentials(id, props));
+            let url = props.get("url")
+                           .cloned()
+                           .unwrap_or_else(|| default_url(backend, repo));
+            let remote = try!(RemoteUrl::parse(&url));
+
+            projects.push(Project {
+                id: id.to_owned(),
+                repo: repo.clone(),
+                url: url,
+                branch: branch,
+                command: command.clone(),
+                backend: backend,
+                native: native,
+                credentials: credentials,
+                status_token: props.get("status_token").cloned(),
+                mirror: props.get("mirror").map(|v| v == "true" || v == "1").unwrap_or(false),
+                lfs: props.get("lfs").map(|v| v == "true" || v == "1").unwrap_or(false),
+                remote: remote,
+            });
+        }
+
+        Ok(Conf {
+            server: server,
+            threads: threads,
+            exec_threads: exec_threads,
+            location: location,
+            gitpath: gitpath,
+            hgpath: hgpath,
+            queue_path: queue_path,
+            max_attempts: max_attempts,
+            backoff_base_secs: backoff_base_secs,
+            projects: projects,
+        })
+    }
+
+    /// Looks a project up by `owner/repo`, matching on the remote parsed from its configured
+    /// url rather than the raw `repo` setting, so payload matching works the same regardless
+    /// of what the project happens to be named in the config file.
+    pub fn get_project(&self, repo: &str) -> Option<&Project> {
+        self.projects.iter().find(|p| p.slug() == repo)
+    }
+}
+
+fn default_url(backend: Backend, repo: &str) -> String {
+    match backend {
+        Backend::Git => format!("https://github.com/{}.git", repo),
+        Backend::Mercurial => format!("https://bitbucket.org/{}", repo),
+    }
+}
+
+fn parse_credentials(id: &str, props: &Properties) -> Result<Option<Credentials>> {
+    if let Some(program) = props.get("askpass") {
+        return Ok(Some(Credentials::Askpass {
+            program: program.clone(),
+            ssh_key_path: props.get("ssh_key").cloned(),
+        }));
+    }
+    if let Some(token) = props.get("https_token") {
+        return Ok(Some(Credentials::HttpsToken { token: token.clone() }));
+    }
+    if let Some(key_path) = props.get("ssh_key") {
+        return Ok(Some(Credentials::SshKey {
+            key_path: key_path.clone(),
+            passphrase: props.get("ssh_passphrase").cloned(),
+        }));
+    }
+    if props.get("ssh_passphrase").is_some() {
+        return Err(Error::app(Reason::InvalidConfig,
+                               format!("Project {} sets ssh_passphrase without ssh_key", id)));
+    }
+    Ok(None)
+}
@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    InvalidRepository,
+    InvalidPath,
+    CommandFailed,
+    InvalidConfig,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    reason: Reason,
+    message: String,
+}
+
+impl Error {
+    pub fn app<S: Into<String>>(reason: Reason, message: S) -> Error {
+        Error {
+            reason: reason,
+            message: message.into(),
+        }
+    }
+
+    pub fn reason(&self) -> Reason {
+        self.reason
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.reason, self.message)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::app(Reason::CommandFailed, format!("{}", err))
+    }
+}
@@ -1,166 +1,220 @@
-use std::str;
+use std::collections::HashSet;
 use std::path::Path;
-use std::process::{Command, Stdio, Output};
-use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
+use backend::{self, VcsBackend};
 use conf::{Conf, Project};
 use error::{Reason, Result, Error};
+use notifier::{self, State};
+use queue::{Job, Queue};
 
-type BytesResult = Result<Vec<u8>>;
-
+/// A bounded pool of workers pulling jobs off the durable queue, one thread per slot.
+/// A per-repo in-flight set keeps two workers from updating the same checkout at once;
+/// repos already being updated are excluded from the claim query so other workers simply
+/// skip past them instead of repeatedly re-claiming and requeuing the same job.
 pub struct Executor {
-    conf: Conf,
-    rx: Receiver<String>,
+    conf: Arc<Conf>,
+    queue: Arc<Queue>,
+    in_flight: Arc<Mutex<HashSet<String>>>,
 }
 
 impl Executor {
-    pub fn new(conf: Conf, rx: Receiver<String>) -> Executor {
+    pub fn new(conf: Conf, queue: Arc<Queue>) -> Executor {
         Executor {
-            conf: conf,
-            rx: rx,
+            conf: Arc::new(conf),
+            queue: queue,
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
     pub fn start(&self) {
-        loop {
-            match self.rx.recv() {
-                Ok(repo) => self.run(&repo),
-                Err(err) => error!("Error occurred while reading updates: {}", err),
-            }
+        let workers = if self.conf.exec_threads == 0 {
+            1
+        } else {
+            self.conf.exec_threads
+        };
+
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                let conf = self.conf.clone();
+                let queue = self.queue.clone();
+                let in_flight = self.in_flight.clone();
+                thread::spawn(move || worker_loop(conf, queue, in_flight))
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.join();
         }
     }
+}
 
-    pub fn run(&self, repo: &str) {
-        match self.update_repo(repo) {
-            Ok(_) => (),
-            Err(err) => error!("Failed to update repository {}: {}", repo, err),
+fn worker_loop(conf: Arc<Conf>, queue: Arc<Queue>, in_flight: Arc<Mutex<HashSet<String>>>) {
+    loop {
+        match claim_free_job(&queue, &in_flight) {
+            Ok(Some(job)) => {
+                run_job(&conf, &queue, &job);
+                in_flight.lock().unwrap().remove(&job.repo);
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(500)),
+            Err(err) => {
+                error!("Error occurred while claiming queued jobs: {}", err);
+                thread::sleep(Duration::from_millis(500));
+            }
         }
     }
+}
 
-    fn update_repo(&self, repo: &str) -> Result<()> {
-        let project = try!(self.get_project(repo));
-        update_project(&self.conf.location, &self.conf.gitpath, project)
-    }
+/// Claims the next due job whose repo isn't already being updated by another worker. Busy
+/// repos are excluded in the claim query itself, so a worker that only finds busy repos gets
+/// `None` back (and sleeps) instead of spinning on requeue-and-retry.
+fn claim_free_job(queue: &Queue, in_flight: &Mutex<HashSet<String>>) -> Result<Option<Job>> {
+    let mut busy = in_flight.lock().unwrap();
+    let job = match try!(queue.claim_next(&busy)) {
+        Some(job) => job,
+        None => return Ok(None),
+    };
+    busy.insert(job.repo.clone());
+    Ok(Some(job))
+}
 
-    fn get_project(&self, repo: &str) -> Result<&Project> {
-        self.conf
-            .get_project(repo)
-            .ok_or(Error::app(Reason::InvalidRepository, "No repository found"))
+fn run_job(conf: &Conf, queue: &Queue, job: &Job) {
+    let result = update_repo(conf, &job.repo);
+    let outcome = match result {
+        Ok(_) => queue.mark_done(job),
+        Err(ref err) => {
+            error!("Failed to update repository {}: {}", job.repo, err);
+            queue.mark_failed(job)
+        }
+    };
+    if let Err(err) = outcome {
+        error!("Failed to update queue state for job {}: {}", job.id, err);
     }
 }
 
-fn update_project(location: &str, git: &str, project: &Project) -> Result<()> {
+fn update_repo(conf: &Conf, repo: &str) -> Result<()> {
+    let project = try!(get_project(conf, repo));
+    update_project(&conf.location, &conf.gitpath, &conf.hgpath, project)
+}
+
+fn get_project<'a>(conf: &'a Conf, repo: &str) -> Result<&'a Project> {
+    conf.get_project(repo)
+        .ok_or(Error::app(Reason::InvalidRepository, "No repository found"))
+}
+
+fn update_project(location: &str, git: &str, hg: &str, project: &Project) -> Result<()> {
     let path_buf = Path::new(location).join(&project.id);
     let path = path_buf.as_path();
 
-    let has_changed = try!(update_repo(git, &path, project));
+    let vcs = backend::for_project(project);
+    let bin = match project.backend {
+        ::conf::Backend::Git => git,
+        ::conf::Backend::Mercurial => hg,
+    };
+
+    let old_sha = if path.exists() {
+        vcs.head_sha(bin, path).ok()
+    } else {
+        None
+    };
+
+    let has_changed = try!(sync_repo(&*vcs, bin, path, project));
 
     if has_changed {
-        let _ = try!(run_from_str(&project.command, path));
-        info!("Repository {} updated successfully", &project.repo);
-        Ok(())
+        run_command(&*vcs, bin, path, project, old_sha)
     } else {
         info!("No changes in repository. Skipping update command.");
         Ok(())
     }
 }
 
-fn update_repo(git: &str, path: &Path, project: &Project) -> Result<bool> {
-    if path.exists() {
+fn sync_repo(vcs: &VcsBackend, bin: &str, path: &Path, project: &Project) -> Result<bool> {
+    if project.mirror {
+        mirror_repo(vcs, bin, path, project)
+    } else if path.exists() {
         info!("Local repo exists: updating");
-        let _ = try!(git_checkout(git, path, &project.branch));
-        let _ = try!(git_remote_update(git, path));
-        let has_changed = try!(git_remote_changed(git, path));
-        let _ = try!(git_pull(git, path));
+        let _ = try!(vcs.checkout(bin, path, &project.branch));
+        let _ = try!(vcs.remote_update(bin, path));
+        let has_changed = try!(vcs.remote_changed(bin, path, &project.branch));
+        let _ = try!(vcs.pull(bin, path, &project.branch));
         Ok(has_changed)
     } else {
         info!("No local repo found: cloning");
-        let _ = try!(git_clone(git, path, &project.repo));
-        let _ = try!(git_checkout(git, path, &project.branch));
+        let _ = try!(vcs.clone_repo(bin, path, &project.url));
+        let _ = try!(vcs.checkout(bin, path, &project.branch));
         Ok(true)
     }
 }
 
-fn git_clone(git: &str, path: &Path, project: &str) -> BytesResult {
-    let path_s = try!(path.to_str().ok_or(Error::app(Reason::InvalidPath, "Invalid project path")));
-    info!("Cloning project {} to {}", project, path_s);
-    run(Command::new(git)
-            .arg("clone")
-            .arg(github_url(project))
-            .arg(path_s),
-        "git clone")
-}
+fn mirror_repo(vcs: &VcsBackend, bin: &str, path: &Path, project: &Project) -> Result<bool> {
+    let has_changed = if path.exists() {
+        info!("Local mirror exists: updating");
+        try!(vcs.update_mirror(bin, path))
+    } else {
+        info!("No local mirror found: cloning");
+        let _ = try!(vcs.clone_mirror(bin, path, &project.url));
+        true
+    };
 
-fn github_url(project: &str) -> String {
-    format!("https://github.com/{}.git", project)
-}
+    if project.lfs {
+        let _ = try!(vcs.lfs_fetch(bin, path));
+    }
 
-fn git_checkout(git: &str, path: &Path, branch: &str) -> BytesResult {
-    info!("Checking out branch {} in {}", branch, path_str(path));
-    run(Command::new(git)
-            .arg("checkout")
-            .arg(branch)
-            .current_dir(path),
-        "git checkout")
+    Ok(has_changed)
 }
 
-fn path_str(path: &Path) -> &str {
-    path.to_str().unwrap_or("[unprintable path]")
-}
+fn run_command(vcs: &VcsBackend, bin: &str, path: &Path, project: &Project, old_sha: Option<String>) -> Result<()> {
+    let notifier = notifier::for_project(project);
+    let new_sha = try!(vcs.head_sha(bin, path));
+    let _ = notifier.notify(project, &new_sha, State::Pending, "Running koukku update command");
 
-fn git_remote_update(git: &str, path: &Path) -> BytesResult {
-    info!("Updating remotes in {}", path_str(path));
-    run(Command::new(git)
-            .arg("remote")
-            .arg("update")
-            .current_dir(path),
-        "git remote update")
-}
+    let env = build_env(vcs, bin, path, project, old_sha.as_ref(), &new_sha);
 
-fn git_pull(git: &str, path: &Path) -> BytesResult {
-    info!("Pulling changes in {}", path_str(path));
-    run(Command::new(git).arg("pull").current_dir(path), "git pull")
-}
-
-fn git_remote_changed(git: &str, path: &Path) -> Result<bool> {
-    let local = try!(run(Command::new(git)
-                             .arg("rev-parse")
-                             .arg("@")
-                             .current_dir(path),
-                         "git rev-parse"));
-    let remote = try!(run(Command::new(git)
-                              .arg("rev-parse")
-                              .arg("@{u}")
-                              .current_dir(path),
-                          "git rev-parse"));
-    Ok(local != remote)
+    match run_from_str(&project.command, path, &env) {
+        Ok(_) => {
+            let _ = notifier.notify(project, &new_sha, State::Success, "Update command succeeded");
+            info!("Repository {} updated successfully", &project.repo);
+            Ok(())
+        }
+        Err(err) => {
+            let _ = notifier.notify(project, &new_sha, State::Failure, &format!("{}", err));
+            Err(err)
+        }
+    }
 }
 
-fn run_from_str(command: &str, path: &Path) -> BytesResult {
-    info!("Running update command {} in {}", command, path_str(path));
-    run(Command::new(command).current_dir(path), command)
-}
+fn build_env(vcs: &VcsBackend,
+             bin: &str,
+             path: &Path,
+             project: &Project,
+             old_sha: Option<&String>,
+             new_sha: &str)
+             -> Vec<(String, String)> {
+    let mut env = vec![("KOUKKU_NEW_SHA".to_owned(), new_sha.to_owned()),
+                       ("KOUKKU_BRANCH".to_owned(), project.branch.clone())];
+
+    if let Some(old_sha) = old_sha {
+        env.push(("KOUKKU_OLD_SHA".to_owned(), old_sha.clone()));
+
+        if let Ok(files) = vcs.changed_files(bin, path, old_sha, new_sha) {
+            env.push(("KOUKKU_CHANGED_FILES".to_owned(), files.join("\n")));
+        }
+        if let Ok(log) = vcs.commit_log(bin, path, old_sha, new_sha) {
+            env.push(("KOUKKU_COMMIT_LOG".to_owned(), log));
+        }
+    }
 
-fn run(command: &mut Command, name: &str) -> BytesResult {
-    command.stdin(Stdio::null())
-           .output()
-           .map_err(Error::from)
-           .and_then(|out| non_zero_to_error(name, out))
+    env
 }
 
-fn non_zero_to_error(cmd: &str, out: Output) -> BytesResult {
-    if out.status.success() {
-        Ok(out.stdout)
-    } else {
-        Err(output_to_error(cmd, out))
+fn run_from_str(command: &str, path: &Path, env: &[(String, String)]) -> backend::BytesResult {
+    info!("Running update command {} in {}", command, backend::path_str(path));
+    let mut cmd = ::std::process::Command::new(command);
+    cmd.current_dir(path);
+    for &(ref key, ref value) in env {
+        cmd.env(key, value);
     }
-}
-
-fn output_to_error(cmd: &str, out: Output) -> Error {
-    let text = str::from_utf8(&out.stderr).unwrap_or("[invalid string]");
-    let msg = format!("Command {} exited with status {}: {}",
-                      cmd,
-                      out.status,
-                      text);
-    Error::app(Reason::CommandFailed, msg)
+    backend::run(&mut cmd, command)
 }
@@ -0,0 +1,177 @@
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str;
+
+use git2::{Cred, FetchOptions, RemoteCallbacks, Repository, ResetType};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+
+use backend::{BytesResult, VcsBackend};
+use conf::Credentials;
+use error::{Error, Reason, Result};
+
+pub struct NativeGitBackend {
+    credentials: Option<Credentials>,
+}
+
+impl NativeGitBackend {
+    pub fn new(credentials: Option<Credentials>) -> NativeGitBackend {
+        NativeGitBackend { credentials: credentials }
+    }
+
+    fn callbacks(&self) -> RemoteCallbacks {
+        let credentials = self.credentials.clone();
+        let mut callbacks = RemoteCallbacks::new();
+        callbacks.credentials(move |_url, username, _allowed| {
+            let user = username.unwrap_or("git");
+            match credentials {
+                Some(Credentials::SshKey { ref key_path, ref passphrase }) => {
+                    Cred::ssh_key(user, None, Path::new(key_path), passphrase.as_ref().map(String::as_str))
+                }
+                Some(Credentials::HttpsToken { ref token }) => Cred::userpass_plaintext(token, ""),
+                Some(Credentials::Askpass { ref program, ssh_key_path: Some(ref key_path) }) => {
+                    let secret = run_askpass(program).unwrap_or_default();
+                    Cred::ssh_key(user, None, Path::new(key_path), Some(&secret))
+                }
+                Some(Credentials::Askpass { ref program, ssh_key_path: None }) => {
+                    let secret = run_askpass(program).unwrap_or_default();
+                    Cred::userpass_plaintext(user, &secret)
+                }
+                None => Cred::default(),
+            }
+        });
+        callbacks
+    }
+
+    fn fetch_options(&self) -> FetchOptions {
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(self.callbacks());
+        opts
+    }
+}
+
+fn run_askpass(program: &str) -> Result<String> {
+    let output = try!(Command::new(program).stdin(Stdio::null()).output().map_err(Error::from));
+    if !output.status.success() {
+        return Err(Error::app(Reason::CommandFailed,
+                               format!("askpass helper {} exited with {}", program, output.status)));
+    }
+    let secret = try!(str::from_utf8(&output.stdout)
+                          .map_err(|_| Error::app(Reason::CommandFailed, "askpass output was not utf-8")));
+    Ok(secret.trim().to_owned())
+}
+
+fn to_error(action: &str, err: ::git2::Error) -> Error {
+    Error::app(Reason::CommandFailed, format!("{} failed: {}", action, err))
+}
+
+/// Resolves `refs/remotes/origin/{branch}` as it stands right now, so callers made after a
+/// fetch see the newly-fetched tip rather than whatever HEAD (possibly detached) points at.
+fn resolve_remote_branch(repo: &Repository, branch: &str) -> Result<::git2::Oid> {
+    let reference = format!("refs/remotes/origin/{}", branch);
+    repo.refname_to_id(&reference).map_err(|e| to_error("git2 resolve branch", e))
+}
+
+impl VcsBackend for NativeGitBackend {
+    fn clone_repo(&self, _bin: &str, path: &Path, url: &str) -> BytesResult {
+        info!("Cloning {} to {} (libgit2)", url, path.display());
+        let mut builder = RepoBuilder::new();
+        builder.fetch_options(self.fetch_options());
+        try!(builder.clone(url, path).map_err(|e| to_error("git2 clone", e)));
+        Ok(Vec::new())
+    }
+
+    fn checkout(&self, _bin: &str, path: &Path, branch: &str) -> BytesResult {
+        info!("Checking out branch {} in {} (libgit2)", branch, path.display());
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let oid = try!(resolve_remote_branch(&repo, branch));
+        let object = try!(repo.find_object(oid, None).map_err(|e| to_error("git2 find object", e)));
+        try!(repo.checkout_tree(&object, Some(CheckoutBuilder::new().force()))
+                 .map_err(|e| to_error("git2 checkout", e)));
+        try!(repo.set_head_detached(oid).map_err(|e| to_error("git2 set head", e)));
+        Ok(Vec::new())
+    }
+
+    fn remote_update(&self, _bin: &str, path: &Path) -> BytesResult {
+        info!("Fetching remote in {} (libgit2)", path.display());
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let mut remote = try!(repo.find_remote("origin").map_err(|e| to_error("git2 find remote", e)));
+        try!(remote.fetch(&[] as &[&str], Some(&mut self.fetch_options()), None)
+                 .map_err(|e| to_error("git2 fetch", e)));
+        Ok(Vec::new())
+    }
+
+    fn pull(&self, _bin: &str, path: &Path, branch: &str) -> BytesResult {
+        info!("Resetting working copy in {} (libgit2)", path.display());
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let oid = try!(resolve_remote_branch(&repo, branch));
+        let commit = try!(repo.find_commit(oid).map_err(|e| to_error("git2 find commit", e)));
+        try!(repo.reset(commit.as_object(), ResetType::Hard, None)
+                 .map_err(|e| to_error("git2 reset", e)));
+        try!(repo.set_head_detached(oid).map_err(|e| to_error("git2 set head", e)));
+        Ok(Vec::new())
+    }
+
+    fn remote_changed(&self, _bin: &str, path: &Path, branch: &str) -> Result<bool> {
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let local = try!(repo.head().map_err(|e| to_error("git2 head", e))).target();
+        let remote = try!(resolve_remote_branch(&repo, branch));
+        Ok(local != Some(remote))
+    }
+
+    fn head_sha(&self, _bin: &str, path: &Path) -> Result<String> {
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let head = try!(repo.head().map_err(|e| to_error("git2 head", e)));
+        let oid = try!(head.target()
+                           .ok_or_else(|| Error::app(Reason::CommandFailed, "HEAD has no target")));
+        Ok(oid.to_string())
+    }
+
+    fn changed_files(&self, _bin: &str, path: &Path, old: &str, new: &str) -> Result<Vec<String>> {
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let old_tree = try!(tree_for(&repo, old));
+        let new_tree = try!(tree_for(&repo, new));
+        let diff = try!(repo.diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)
+                            .map_err(|e| to_error("git2 diff", e)));
+
+        let mut files = Vec::new();
+        try!(diff.foreach(&mut |delta, _progress| {
+                              if let Some(path) = delta.new_file().path().or_else(|| delta.old_file().path()) {
+                                  files.push(path.to_string_lossy().into_owned());
+                              }
+                              true
+                          },
+                          None,
+                          None,
+                          None)
+                 .map_err(|e| to_error("git2 diff foreach", e)));
+        Ok(files)
+    }
+
+    fn commit_log(&self, _bin: &str, path: &Path, old: &str, new: &str) -> Result<String> {
+        let repo = try!(Repository::open(path).map_err(|e| to_error("git2 open", e)));
+        let old_oid = try!(oid_for(old));
+        let new_oid = try!(oid_for(new));
+
+        let mut revwalk = try!(repo.revwalk().map_err(|e| to_error("git2 revwalk", e)));
+        try!(revwalk.push(new_oid).map_err(|e| to_error("git2 revwalk push", e)));
+        try!(revwalk.hide(old_oid).map_err(|e| to_error("git2 revwalk hide", e)));
+
+        let mut lines = Vec::new();
+        for oid in revwalk {
+            let oid = try!(oid.map_err(|e| to_error("git2 revwalk", e)));
+            let commit = try!(repo.find_commit(oid).map_err(|e| to_error("git2 find commit", e)));
+            lines.push(format!("{}\0{}", oid, commit.summary().unwrap_or("")));
+        }
+        Ok(lines.join("\n"))
+    }
+}
+
+fn oid_for(rev: &str) -> Result<::git2::Oid> {
+    ::git2::Oid::from_str(rev).map_err(|e| to_error("git2 parse oid", e))
+}
+
+fn tree_for<'a>(repo: &'a Repository, rev: &str) -> Result<::git2::Tree<'a>> {
+    let oid = try!(oid_for(rev));
+    let commit = try!(repo.find_commit(oid).map_err(|e| to_error("git2 find commit", e)));
+    commit.tree().map_err(|e| to_error("git2 tree", e))
+}
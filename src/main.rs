@@ -1,6 +1,8 @@
 extern crate rustc_serialize;
 extern crate ini;
 extern crate openssl;
+extern crate git2;
+extern crate rusqlite;
 #[macro_use]
 extern crate hyper;
 #[macro_use]
@@ -14,11 +16,15 @@ mod header;
 mod server;
 mod conf;
 mod payload;
+mod backend;
+mod git2_backend;
+mod notifier;
+mod queue;
 mod exec;
 
 use clap::{Arg, App};
 use std::thread;
-use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::io::{self, Write};
 
 macro_rules! try_log(
@@ -59,12 +65,14 @@ fn start(config: &str) {
     let threads = conf.threads;
     let projects = conf.projects.clone();
 
-    let (tx, rx) = channel();
-    let executor = exec::Executor::new(conf, rx);
+    let queue = Arc::new(try_log!(queue::Queue::open(&conf.queue_path,
+                                                      conf.max_attempts,
+                                                      conf.backoff_base_secs)));
+    let executor = exec::Executor::new(conf, queue.clone());
 
     info!("Starting koukku server");
 
     thread::spawn(move || executor.start());
 
-    let _ = try_log!(server::start(&server, threads, projects, tx));
+    let _ = try_log!(server::start(&server, threads, projects, queue));
 }
@@ -0,0 +1,120 @@
+use std::io::Read;
+
+use hyper::Client;
+use hyper::header::{ContentType, Headers, UserAgent};
+
+use conf::{Project, RemoteUrl};
+use error::{Error, Reason, Result};
+
+#[derive(Debug)]
+pub enum State {
+    Pending,
+    Success,
+    Failure,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            State::Pending => "pending",
+            State::Success => "success",
+            State::Failure => "failure",
+        }
+    }
+}
+
+pub trait Notifier {
+    fn notify(&self, project: &Project, sha: &str, state: State, description: &str) -> Result<()>;
+}
+
+/// Matches koukku's previous behavior: commit status changes are only logged locally.
+pub struct NullNotifier;
+
+impl Notifier for NullNotifier {
+    fn notify(&self, _project: &Project, sha: &str, state: State, description: &str) -> Result<()> {
+        info!("Commit status for {} would be '{}': {}", sha, state.as_str(), description);
+        Ok(())
+    }
+}
+
+/// Reports the outcome of the update command back to the forge as a commit status.
+pub struct ForgeNotifier {
+    token: String,
+}
+
+impl ForgeNotifier {
+    pub fn new(token: String) -> ForgeNotifier {
+        ForgeNotifier { token: token }
+    }
+}
+
+impl Notifier for ForgeNotifier {
+    fn notify(&self, project: &Project, sha: &str, state: State, description: &str) -> Result<()> {
+        let remote = try!(RemoteUrl::parse(&project.url));
+        let url = format!("{}/repos/{}/{}/statuses/{}",
+                          api_base(&remote),
+                          remote.owner,
+                          remote.repo,
+                          sha);
+        let body = format!("{{\"state\":{},\"description\":{},\"context\":\"koukku\"}}",
+                          json_string(state.as_str()),
+                          json_string(description));
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::json());
+        headers.set(UserAgent("koukku".to_owned()));
+        headers.set_raw("Authorization", vec![auth_header(&remote, &self.token).into_bytes()]);
+
+        let client = Client::new();
+        let mut response = try!(client.post(&url)
+                                       .headers(headers)
+                                       .body(&body)
+                                       .send()
+                                       .map_err(|e| {
+                                           Error::app(Reason::CommandFailed,
+                                                      format!("Failed to reach {}: {}", url, e))
+                                       }));
+
+        let mut text = String::new();
+        let _ = response.read_to_string(&mut text);
+
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            Err(Error::app(Reason::CommandFailed,
+                            format!("Status update to {} rejected with {}: {}", url, response.status, text)))
+        }
+    }
+}
+
+/// github.com is only ever served from the `api.` subdomain; everything else is assumed to
+/// be a Gitea/ForgeJo instance, whose API lives under `/api/v1` on the same host.
+fn api_base(remote: &RemoteUrl) -> String {
+    if remote.host == "github.com" {
+        "https://api.github.com".to_owned()
+    } else {
+        format!("https://{}/api/v1", remote.host)
+    }
+}
+
+/// GitHub wants a bearer token; Gitea/ForgeJo (including older releases that predate Bearer
+/// support) expect the `token` scheme instead.
+fn auth_header(remote: &RemoteUrl, token: &str) -> String {
+    if remote.host == "github.com" {
+        format!("Bearer {}", token)
+    } else {
+        format!("token {}", token)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let escaped = s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+pub fn for_project(project: &Project) -> Box<Notifier> {
+    match project.status_token {
+        Some(ref token) => Box::new(ForgeNotifier::new(token.clone())),
+        None => Box::new(NullNotifier),
+    }
+}
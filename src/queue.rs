@@ -0,0 +1,188 @@
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, ToSql};
+
+use error::{Error, Reason, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Dead,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Dead => "dead",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: i64,
+    pub repo: String,
+    pub delivery_id: Option<String>,
+    pub attempt: u32,
+}
+
+pub struct Queue {
+    conn: Mutex<Connection>,
+    max_attempts: u32,
+    backoff_base_secs: i64,
+}
+
+impl Queue {
+    pub fn open(path: &str, max_attempts: u32, backoff_base_secs: i64) -> Result<Queue> {
+        let conn = try!(Connection::open(path).map_err(to_error));
+        try!(conn.execute_batch("
+            CREATE TABLE IF NOT EXISTS jobs (
+                id              INTEGER PRIMARY KEY AUTOINCREMENT,
+                repo            TEXT NOT NULL,
+                delivery_id     TEXT,
+                received_at     INTEGER NOT NULL,
+                next_attempt_at INTEGER NOT NULL,
+                attempt         INTEGER NOT NULL DEFAULT 0,
+                status          TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS jobs_status_idx ON jobs (status, next_attempt_at);
+        ")
+                 .map_err(to_error));
+
+        let queue = Queue {
+            conn: Mutex::new(conn),
+            max_attempts: max_attempts,
+            backoff_base_secs: backoff_base_secs,
+        };
+        try!(queue.recover_stuck());
+        Ok(queue)
+    }
+
+    /// Re-queues jobs left `running` by a process that died mid-update.
+    fn recover_stuck(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let changed = try!(conn.execute("UPDATE jobs SET status = ? WHERE status = ?",
+                                         &[&JobStatus::Pending.as_str(), &JobStatus::Running.as_str()])
+                                .map_err(to_error));
+        if changed > 0 {
+            warn!("Recovered {} job(s) stuck in 'running' from a previous crash", changed);
+        }
+        Ok(())
+    }
+
+    /// Enqueues a repo update, coalescing with any already-*pending* job for the same repo.
+    ///
+    /// A *running* job for the repo does not stop a new row being inserted: it's already
+    /// fetched whatever was current when it claimed the job, so a push landing after that
+    /// would otherwise sit undeployed until some unrelated later push happened to enqueue
+    /// another job. Scheduling one pending re-run guarantees the latest push always gets
+    /// picked up once the in-flight update finishes.
+    pub fn enqueue(&self, repo: &str, delivery_id: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let pending: i64 = try!(conn.query_row("SELECT COUNT(*) FROM jobs WHERE repo = ? AND status = ?",
+                                                &[&repo, &JobStatus::Pending.as_str()],
+                                                |row| row.get(0))
+                                     .map_err(to_error));
+        if pending > 0 {
+            info!("Coalescing webhook for {} into already-pending job", repo);
+            return Ok(());
+        }
+
+        let now = now_secs();
+        try!(conn.execute("INSERT INTO jobs (repo, delivery_id, received_at, next_attempt_at, attempt, status) \
+                            VALUES (?, ?, ?, ?, 0, ?)",
+                           &[&repo, &delivery_id, &now, &now, &JobStatus::Pending.as_str()])
+                 .map_err(to_error));
+        Ok(())
+    }
+
+    /// Claims the oldest due `pending` job whose repo isn't in `exclude`, marking it `running`.
+    /// Excluding in-flight repos here (rather than claiming blind and requeuing) means a worker
+    /// that only sees busy repos gets `None` back instead of spinning.
+    pub fn claim_next(&self, exclude: &HashSet<String>) -> Result<Option<Job>> {
+        let conn = self.conn.lock().unwrap();
+        let now = now_secs();
+
+        let placeholders = vec!["?"; exclude.len()].join(", ");
+        let query = format!("SELECT id, repo, delivery_id, attempt FROM jobs \
+                              WHERE status = ? AND next_attempt_at <= ? {} \
+                              ORDER BY received_at ASC LIMIT 1",
+                             if exclude.is_empty() {
+                                 String::new()
+                             } else {
+                                 format!("AND repo NOT IN ({})", placeholders)
+                             });
+
+        let pending = JobStatus::Pending.as_str();
+        let mut params: Vec<&ToSql> = vec![&pending, &now];
+        for repo in exclude {
+            params.push(repo);
+        }
+
+        let found = conn.query_row(&query, &params, |row| {
+            Job {
+                id: row.get(0),
+                repo: row.get(1),
+                delivery_id: row.get(2),
+                attempt: row.get::<_, i64>(3) as u32,
+            }
+        });
+
+        match found {
+            Ok(job) => {
+                try!(conn.execute("UPDATE jobs SET status = ? WHERE id = ?",
+                                   &[&JobStatus::Running.as_str(), &job.id])
+                         .map_err(to_error));
+                Ok(Some(job))
+            }
+            Err(::rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(err) => Err(to_error(err)),
+        }
+    }
+
+    pub fn mark_done(&self, job: &Job) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        try!(conn.execute("UPDATE jobs SET status = ? WHERE id = ?",
+                           &[&JobStatus::Done.as_str(), &job.id])
+                 .map_err(to_error));
+        Ok(())
+    }
+
+    /// Reschedules with exponential backoff, or marks `dead` once `max_attempts` attempts have failed.
+    pub fn mark_failed(&self, job: &Job) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let attempt = job.attempt + 1;
+        if attempt >= self.max_attempts {
+            try!(conn.execute("UPDATE jobs SET status = ?, attempt = ? WHERE id = ?",
+                               &[&JobStatus::Dead.as_str(), &(attempt as i64), &job.id])
+                     .map_err(to_error));
+            error!("Job for {} failed {} attempts, marking dead", job.repo, self.max_attempts);
+        } else {
+            let delay = self.backoff_base_secs * (1i64 << (attempt - 1).min(16));
+            let next_attempt_at = now_secs() + delay;
+            try!(conn.execute("UPDATE jobs SET status = ?, attempt = ?, next_attempt_at = ? WHERE id = ?",
+                               &[&JobStatus::Pending.as_str(), &(attempt as i64), &next_attempt_at, &job.id])
+                     .map_err(to_error));
+            info!("Job for {} failed (attempt {}), retrying in {}s", job.repo, attempt, delay);
+        }
+        Ok(())
+    }
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn to_error(err: ::rusqlite::Error) -> Error {
+    Error::app(Reason::CommandFailed, format!("queue error: {}", err))
+}